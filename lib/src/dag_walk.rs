@@ -0,0 +1,38 @@
+// Copyright 2021-2022 The Jujutsu Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::hash::Hash;
+
+/// Filters `items` down to those that are not an ancestor (per
+/// `neighbors_fn`, e.g. "parents") of any other item in `items`.
+pub fn heads<T, ID, N, I>(items: Vec<T>, neighbors_fn: &N, id_fn: &I) -> Vec<T>
+where
+    ID: Hash + Eq,
+    N: Fn(&T) -> Vec<T>,
+    I: Fn(&T) -> ID,
+{
+    let mut ancestor_ids: HashSet<ID> = HashSet::new();
+    let mut stack: Vec<T> = items.iter().flat_map(neighbors_fn).collect();
+    while let Some(item) = stack.pop() {
+        let id = id_fn(&item);
+        if ancestor_ids.insert(id) {
+            stack.extend(neighbors_fn(&item));
+        }
+    }
+    items
+        .into_iter()
+        .filter(|item| !ancestor_ids.contains(&id_fn(item)))
+        .collect()
+}
@@ -0,0 +1,52 @@
+// Copyright 2021-2022 The Jujutsu Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+/// An advisory lock backed by exclusive file creation. Dropping it releases
+/// the lock.
+pub struct FileLock {
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Blocks until the lock is acquired.
+    pub fn lock(path: PathBuf) -> Self {
+        loop {
+            if let Some(lock) = Self::try_lock(path.clone()) {
+                return lock;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Makes a single, non-blocking attempt to acquire the lock.
+    pub fn try_lock(path: PathBuf) -> Option<Self> {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .ok()
+            .map(|_| FileLock { path })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
@@ -0,0 +1,578 @@
+// Copyright 2021-2022 The Jujutsu Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use itertools::Itertools;
+use thiserror::Error;
+
+use crate::lock::FileLock;
+use crate::op_store::{OpStore, OperationId};
+use crate::operation::Operation;
+
+/// Manages the set of current heads of the operation log.
+pub trait OpHeadsStore: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn add_op_head(&self, id: &OperationId);
+
+    fn remove_op_head(&self, id: &OperationId);
+
+    fn get_op_heads(&self) -> Vec<OperationId>;
+
+    /// Locks the op-heads store, blocking until the lock is available.
+    fn lock(&self) -> LockedOpHeads;
+
+    /// Like `lock()`, but gives up and returns `None` if the lock can't be
+    /// acquired within `deadline`, instead of blocking indefinitely. Callers
+    /// can use this to fall back to read-only reconciliation on filesystems
+    /// (e.g. some NFS mounts) where advisory locking is unreliable or a no-op.
+    fn try_lock(&self, deadline: Duration) -> Option<LockedOpHeads>;
+
+    fn get_heads(&self, op_store: &Arc<dyn OpStore>) -> Result<OpHeads, OpHeadResolutionError>;
+}
+
+#[derive(Debug, Error)]
+pub enum OpHeadResolutionError {
+    #[error("Operation log has no heads")]
+    NoHeads,
+}
+
+pub enum OpHeads {
+    // Boxed since `Operation` is much larger than `Unresolved`'s fields (see
+    // `OpHeadsResolution::Single`, which has the same shape for the same
+    // reason) -- otherwise `clippy::large_enum_variant` fires.
+    Single(Box<Operation>),
+    Unresolved {
+        locked_op_heads: LockedOpHeads,
+        op_heads: Vec<Operation>,
+    },
+}
+
+/// Called once the caller has picked (or created a merge operation for) the
+/// new single head, to let the store record that decision.
+pub trait LockedOpHeadsResolver {
+    fn finish(&self, new_op: &Operation);
+}
+
+pub struct LockedOpHeads {
+    resolver: Box<dyn LockedOpHeadsResolver>,
+}
+
+impl LockedOpHeads {
+    pub fn new(resolver: Box<dyn LockedOpHeadsResolver>) -> Self {
+        LockedOpHeads { resolver }
+    }
+
+    pub fn finish(self, new_op: &Operation) {
+        self.resolver.finish(new_op);
+    }
+}
+
+/// Resolver used when a backend couldn't acquire its lock before its
+/// deadline. We still hand back a merged view so reads succeed, but
+/// `finish` is a no-op: we don't hold the lock, so writing here could race
+/// with whichever process does hold it (or silently overwrite its work on a
+/// filesystem where advisory locking doesn't work at all, e.g. some NFS
+/// mounts).
+pub(crate) struct NoopLockResolver;
+
+impl LockedOpHeadsResolver for NoopLockResolver {
+    fn finish(&self, _new_op: &Operation) {}
+}
+
+/// Default deadline `get_heads` gives a backend's `try_lock` before falling
+/// back to read-only reconciliation.
+pub(crate) const LOCK_DEADLINE: Duration = Duration::from_millis(200);
+
+/// Cap on the exponential backoff between `try_lock_with_backoff` attempts,
+/// so attempts keep coming at least this often instead of the waits growing
+/// until one long sleep uses up the rest of `deadline`.
+const MAX_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Repeatedly attempts to acquire the advisory lock at `lock_path`, backing
+/// off exponentially between attempts (starting at 1ms, capped at
+/// `MAX_LOCK_RETRY_INTERVAL`) until `deadline` elapses, then gives up and
+/// returns `None` instead of blocking indefinitely like `FileLock::lock`.
+pub(crate) fn try_lock_with_backoff(lock_path: &Path, deadline: Duration) -> Option<FileLock> {
+    let start = Instant::now();
+    let mut retry_interval = Duration::from_millis(1);
+    loop {
+        if let Some(lock) = FileLock::try_lock(lock_path.to_path_buf()) {
+            return Some(lock);
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= deadline {
+            return None;
+        }
+        thread::sleep(retry_interval.min(deadline - elapsed));
+        retry_interval = (retry_interval * 2).min(MAX_LOCK_RETRY_INTERVAL);
+    }
+}
+
+/// Intermediate result of resolving a set of op head ids, before we decide
+/// what kind of `LockedOpHeads` (if any) to attach. `Single` is boxed since
+/// `Operation` is much larger than the `Vec` in `Divergent`.
+pub(crate) enum OpHeadsResolution {
+    Single(Box<Operation>),
+    Divergent(Vec<Operation>),
+}
+
+/// Resolves `op_head_ids` down to either a single already-current head or a
+/// sorted, ancestor-pruned list of divergent heads, using `prune` to decide
+/// how ancestors are removed (on disk vs. in memory only).
+pub(crate) fn resolve_op_heads(
+    op_store: &Arc<dyn OpStore>,
+    op_head_ids: Vec<OperationId>,
+    prune: impl FnOnce(Vec<Operation>) -> Vec<Operation>,
+) -> Result<OpHeadsResolution, OpHeadResolutionError> {
+    if op_head_ids.is_empty() {
+        return Err(OpHeadResolutionError::NoHeads);
+    }
+
+    if op_head_ids.len() == 1 {
+        let op_head_id = op_head_ids.into_iter().next().unwrap();
+        let op_head = op_store.read_operation(&op_head_id).unwrap();
+        return Ok(OpHeadsResolution::Single(Box::new(Operation::new(
+            op_store.clone(),
+            op_head_id,
+            op_head,
+        ))));
+    }
+
+    let op_heads = op_head_ids
+        .iter()
+        .map(|op_id: &OperationId| {
+            let data = op_store.read_operation(op_id).unwrap();
+            Operation::new(op_store.clone(), op_id.clone(), data)
+        })
+        .collect_vec();
+    let mut op_heads = prune(op_heads);
+
+    if op_heads.len() == 1 {
+        return Ok(OpHeadsResolution::Single(Box::new(op_heads.pop().unwrap())));
+    }
+
+    op_heads.sort_by_key(OpOrderKey::of);
+    Ok(OpHeadsResolution::Divergent(op_heads))
+}
+
+/// Shared `get_heads` implementation for `OpHeadsStore` backends: resolves
+/// the current head ids down to either a single head, or (after acquiring
+/// the lock, or falling back to a read-only merge if that times out) a
+/// sorted, pruned list of divergent heads.
+///
+/// `get_op_heads` re-reads the current head ids (called more than once,
+/// since another process may resolve the heads while we wait for the
+/// lock); `try_lock` and the two `prune_*` hooks are each used at most
+/// once, depending on whether the lock was acquired. `prune_locked` runs
+/// while the `LockedOpHeads` returned by `try_lock` is still alive and
+/// holding the store's lock, so it must not try to acquire that same lock
+/// again (directly, or via a self-locking method like `add_op_head`) or it
+/// will deadlock against itself.
+pub(crate) fn get_heads(
+    op_store: &Arc<dyn OpStore>,
+    get_op_heads: impl Fn() -> Vec<OperationId>,
+    try_lock: impl FnOnce(Duration) -> Option<LockedOpHeads>,
+    prune_locked: impl FnOnce(Vec<Operation>) -> Vec<Operation>,
+    prune_readonly: impl FnOnce(Vec<Operation>) -> Vec<Operation>,
+) -> Result<OpHeads, OpHeadResolutionError> {
+    let op_head_ids = get_op_heads();
+    if op_head_ids.is_empty() {
+        return Err(OpHeadResolutionError::NoHeads);
+    }
+    if op_head_ids.len() == 1 {
+        let op_head_id = op_head_ids.into_iter().next().unwrap();
+        let op_head = op_store.read_operation(&op_head_id).unwrap();
+        return Ok(OpHeads::Single(Box::new(Operation::new(
+            op_store.clone(),
+            op_head_id,
+            op_head,
+        ))));
+    }
+
+    // There are multiple heads. We try to take the lock (without blocking
+    // indefinitely, in case it's held by a stuck process or doesn't work at all
+    // on this filesystem), then check if there are still multiple heads (it's
+    // likely that another process was in the process of deleting one of them).
+    // If there are still multiple heads, we attempt to merge all the views into
+    // one. We then write that view and a corresponding operation to the
+    // op-store.
+    // Note that the locking isn't necessary for correctness; we take the lock
+    // only to prevent other concurrent processes from doing the same work (and
+    // producing another set of divergent heads).
+    match try_lock(LOCK_DEADLINE) {
+        Some(locked_op_heads) => {
+            let op_head_ids = get_op_heads();
+            match resolve_op_heads(op_store, op_head_ids, prune_locked)? {
+                OpHeadsResolution::Single(op_head) => Ok(OpHeads::Single(op_head)),
+                OpHeadsResolution::Divergent(op_heads) => Ok(OpHeads::Unresolved {
+                    locked_op_heads,
+                    op_heads,
+                }),
+            }
+        }
+        None => {
+            // We couldn't get the lock within the deadline (maybe a peer is
+            // holding it, maybe locking just doesn't work here). Fall back to
+            // a read-only reconciliation so callers don't hang: re-read the
+            // heads in case a peer already resolved them while we were
+            // waiting, otherwise merge in memory without writing anything.
+            let op_head_ids = get_op_heads();
+            match resolve_op_heads(op_store, op_head_ids, prune_readonly)? {
+                OpHeadsResolution::Single(op_head) => Ok(OpHeads::Single(op_head)),
+                OpHeadsResolution::Divergent(op_heads) => Ok(OpHeads::Unresolved {
+                    locked_op_heads: LockedOpHeads::new(Box::new(NoopLockResolver)),
+                    op_heads,
+                }),
+            }
+        }
+    }
+}
+
+/// Records the backend and on-disk format version of an op-heads store.
+/// Written into the store directory at `init` time and consulted by
+/// `load`, so a backend knows what migrations (if any) need to run before
+/// reading the store, rather than guessing from which files happen to
+/// exist. A directory with no `requirements` file predates this mechanism
+/// and is treated as version 0.
+pub struct Requirements {
+    pub backend: String,
+    pub version: u32,
+}
+
+impl Requirements {
+    pub fn read(dir: &Path, default_backend: &str) -> Self {
+        match fs::read_to_string(dir.join("requirements")) {
+            Ok(content) => {
+                let mut lines = content.lines();
+                let backend = lines.next().unwrap_or(default_backend).to_string();
+                let version = lines.next().and_then(|v| v.parse().ok()).unwrap_or(0);
+                Requirements { backend, version }
+            }
+            Err(_) => Requirements {
+                backend: default_backend.to_string(),
+                version: 0,
+            },
+        }
+    }
+
+    pub fn write(&self, dir: &Path) {
+        // Write to a temporary file and rename it into place so a reader (or a
+        // retried migration step) never observes a missing or truncated
+        // `requirements` file, which would read back as version 0 and rerun
+        // migrations that already completed.
+        let tmp_path = dir.join("requirements.tmp");
+        fs::write(&tmp_path, format!("{}\n{}\n", self.backend, self.version)).unwrap();
+        fs::rename(&tmp_path, dir.join("requirements")).unwrap();
+    }
+}
+
+/// One forward step in a backend's migration chain, taking a store
+/// directory from `from_version` to `to_version`. Steps are looked up by
+/// `from_version` and applied in sequence until the directory reaches the
+/// backend's current format version.
+pub struct MigrationStep {
+    pub from_version: u32,
+    pub to_version: u32,
+    pub migrate: fn(&Path),
+}
+
+/// Runs `migrations` over `dir` until its format version reaches
+/// `format_version`, then returns the up-to-date requirements. A
+/// freshly-initialized store is already at `format_version`, so this is a
+/// no-op for it.
+///
+/// Panics if `dir` already has a `requirements` file naming a *different*
+/// backend: that means the caller asked the wrong backend to load `dir`
+/// (e.g. by calling `SingleFileOpHeadsStore::load` directly on a directory
+/// written by `SimpleOpHeadsStore`), and silently proceeding would read no
+/// heads and overwrite `requirements` with the wrong backend name, making
+/// the on-disk data look lost. Callers that don't already know which
+/// backend a directory uses should go through `load`, which dispatches on
+/// `requirements.backend` instead of assuming one.
+pub fn migrate(
+    dir: &Path,
+    backend_name: &str,
+    format_version: u32,
+    migrations: &[MigrationStep],
+) -> Requirements {
+    let mut requirements = Requirements::read(dir, backend_name);
+    assert_eq!(
+        requirements.backend, backend_name,
+        "{} has a {} store, not a {} store",
+        dir.display(),
+        requirements.backend,
+        backend_name
+    );
+    while requirements.version < format_version {
+        let step = migrations
+            .iter()
+            .find(|step| step.from_version == requirements.version)
+            .unwrap_or_else(|| {
+                panic!(
+                    "don't know how to migrate {} from version {}",
+                    backend_name, requirements.version
+                )
+            });
+        (step.migrate)(dir);
+        requirements.version = step.to_version;
+    }
+    requirements.backend = backend_name.to_string();
+    requirements
+}
+
+/// Opens the op-heads store at `dir`, picking the backend named in its
+/// `requirements` file (defaulting to `simple_op_heads_store` for
+/// directories that predate the file, i.e. version 0). This is the
+/// dispatching entry point callers should use when they don't already know
+/// which backend a directory was written with; calling a specific backend's
+/// own `load` on the wrong directory is a programming error that `migrate`
+/// now catches via an assertion rather than silently losing data.
+pub fn load(dir: &Path) -> Box<dyn OpHeadsStore> {
+    let requirements = Requirements::read(dir, crate::simple_op_heads_store::BACKEND_NAME);
+    match requirements.backend.as_str() {
+        crate::single_file_op_heads_store::BACKEND_NAME => Box::new(
+            crate::single_file_op_heads_store::SingleFileOpHeadsStore::load(dir),
+        ),
+        _ => Box::new(crate::simple_op_heads_store::SimpleOpHeadsStore::load(dir)),
+    }
+}
+
+/// Switches the op-heads store at `dir` to `single_file_op_heads_store`,
+/// migrating the current heads from whichever backend is selected today.
+/// No-op if `dir` is already using the single-file backend.
+pub fn migrate_to_single_file(dir: &Path) {
+    let requirements = Requirements::read(dir, crate::simple_op_heads_store::BACKEND_NAME);
+    if requirements.backend == crate::single_file_op_heads_store::BACKEND_NAME {
+        return;
+    }
+    // Bring a version-0 (flat layout) store up to the `simple_op_heads`
+    // subdirectory layout first, so its heads are found below.
+    crate::simple_op_heads_store::SimpleOpHeadsStore::load(dir);
+    crate::single_file_op_heads_store::SingleFileOpHeadsStore::migrate_from_simple_op_heads(dir);
+    Requirements {
+        backend: crate::single_file_op_heads_store::BACKEND_NAME.to_string(),
+        version: crate::single_file_op_heads_store::FORMAT_VERSION,
+    }
+    .write(dir);
+}
+
+/// Deterministic ordering key for divergent op heads, used in place of
+/// `end_time`: a wall-clock timestamp is unreliable across machines with
+/// skewed clocks and gives non-deterministic merge parent ordering. Ordering
+/// by `Clocked` variants compares the operation's Lamport clock first, then
+/// breaks ties on `OperationId` bytes so independent participants building
+/// the same merge always agree. Operations written before the Lamport clock
+/// existed have `metadata.clock == 0`; they sort as `Legacy` (by `end_time`,
+/// then id) and before any `Clocked` operation, since the clock only exists
+/// for operations created after this change landed.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub enum OpOrderKey {
+    Legacy(crate::op_store::MillisSinceEpoch, OperationId),
+    Clocked(u64, OperationId),
+}
+
+impl OpOrderKey {
+    pub fn of(op: &Operation) -> Self {
+        let metadata = &op.store_operation().metadata;
+        if metadata.clock == 0 {
+            OpOrderKey::Legacy(metadata.end_time.timestamp, op.id().clone())
+        } else {
+            OpOrderKey::Clocked(metadata.clock, op.id().clone())
+        }
+    }
+}
+
+/// A small persisted side-table recording, for each op head we've added,
+/// which other heads it directly obsoleted (its parents that were current
+/// heads at the time it was added, per `LockedOpHeadsResolver::finish`).
+///
+/// `handle_ancestor_ops`-style pruning uses this to recognize the common
+/// case (e.g. two heads where one is simply a later descendant of the
+/// other, left behind by a racing process that added the new head but
+/// hasn't removed the old one yet) via a table lookup, instead of issuing
+/// a full `dag_walk` over the operation graph on every `get_heads` call.
+/// It falls back to `dag_walk` whenever the table doesn't fully explain
+/// the current head set, e.g. right after upgrading from a version with
+/// no table, or when heads are genuinely divergent rather than stale.
+pub struct ObsoletionTable {
+    /// Maps a head to the heads it's recorded to have obsoleted.
+    obsoletes: HashMap<OperationId, Vec<OperationId>>,
+}
+
+impl ObsoletionTable {
+    pub fn read(dir: &Path) -> Self {
+        let obsoletes = match fs::read_to_string(dir.join("obsoletes")) {
+            Ok(content) => content
+                .lines()
+                .filter_map(|line| {
+                    let (head, rest) = line.split_once(' ')?;
+                    let head = OperationId::new(hex::decode(head).ok()?);
+                    let obsoleted = rest
+                        .split(',')
+                        .filter(|s| !s.is_empty())
+                        .filter_map(|s| hex::decode(s).ok())
+                        .map(OperationId::new)
+                        .collect();
+                    Some((head, obsoleted))
+                })
+                .collect(),
+            Err(_) => HashMap::new(),
+        };
+        ObsoletionTable { obsoletes }
+    }
+
+    pub fn write(&self, dir: &Path) {
+        let mut content = String::new();
+        for (head, obsoleted) in &self.obsoletes {
+            content.push_str(&head.hex());
+            content.push(' ');
+            content.push_str(&obsoleted.iter().map(OperationId::hex).join(","));
+            content.push('\n');
+        }
+        let tmp_path = dir.join("obsoletes.tmp");
+        fs::write(&tmp_path, content).unwrap();
+        fs::rename(&tmp_path, dir.join("obsoletes")).unwrap();
+    }
+
+    /// Records that `head` obsoletes `obsoleted`. Entries for the
+    /// now-obsoleted ids are dropped since they can no longer be heads
+    /// themselves, which keeps the table from growing without bound.
+    pub fn record(&mut self, head: OperationId, obsoleted: Vec<OperationId>) {
+        for id in &obsoleted {
+            self.obsoletes.remove(id);
+        }
+        self.obsoletes.insert(head, obsoleted);
+    }
+
+    /// Transitively expands what `head` is known to obsolete, or `None` if
+    /// we have no entry for it at all.
+    fn transitive_obsoletes(&self, head: &OperationId) -> Option<HashSet<OperationId>> {
+        let mut result = HashSet::new();
+        let mut frontier = self.obsoletes.get(head)?.clone();
+        while let Some(id) = frontier.pop() {
+            if result.insert(id.clone()) {
+                if let Some(more) = self.obsoletes.get(&id) {
+                    frontier.extend(more.iter().cloned());
+                }
+            }
+        }
+        Some(result)
+    }
+
+    /// Attempts to prune `op_heads` down to its actual heads using only
+    /// table lookups. Returns `None` (signaling "fall back to `dag_walk`")
+    /// unless one of the heads is recorded to transitively obsolete every
+    /// other head in the set, i.e. unless the table fully explains away
+    /// all but one survivor.
+    pub fn prune(&self, op_heads: &[Operation]) -> Option<Operation> {
+        if op_heads.len() < 2 {
+            return None;
+        }
+        let ids: HashSet<OperationId> = op_heads.iter().map(|op| op.id().clone()).collect();
+        op_heads.iter().find(|candidate| {
+            let Some(obsoleted) = self.transitive_obsoletes(candidate.id()) else {
+                return false;
+            };
+            ids.iter()
+                .all(|id| id == candidate.id() || obsoleted.contains(id))
+        })
+        .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use super::{get_heads, ObsoletionTable, OpHeads};
+    use crate::op_store::testutils::TestOpStore;
+    use crate::op_store::{OpStore, OperationId};
+
+    #[test]
+    fn test_obsoletion_table_read_write_round_trip() {
+        let test_dir = testutils::new_temp_dir();
+
+        let op1 = OperationId::from_hex("111111");
+        let op2 = OperationId::from_hex("222222");
+        let merge = OperationId::from_hex("333333");
+
+        let mut table = ObsoletionTable::read(test_dir.path());
+        table.record(merge.clone(), vec![op1.clone(), op2.clone()]);
+        table.write(test_dir.path());
+
+        let table = ObsoletionTable::read(test_dir.path());
+        assert_eq!(
+            table.transitive_obsoletes(&merge),
+            Some(HashSet::from([op1, op2]))
+        );
+    }
+
+    #[test]
+    fn test_obsoletion_table_unrecorded_head_has_no_entry() {
+        let test_dir = testutils::new_temp_dir();
+        let op1 = OperationId::from_hex("111111");
+        let op2 = OperationId::from_hex("222222");
+
+        let mut table = ObsoletionTable::read(test_dir.path());
+        table.record(op2.clone(), vec![op1.clone()]);
+
+        // Incomplete table (e.g. a third, never-recorded head) can't be used to
+        // prune, so `prune` must fall back to `dag_walk` instead of guessing.
+        assert!(table
+            .transitive_obsoletes(&OperationId::from_hex("333333"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_get_heads_orders_divergent_heads_by_lamport_clock() {
+        let test_store = Arc::new(TestOpStore::new());
+        let op_store: Arc<dyn OpStore> = test_store.clone();
+        let root = test_store.add_operation(vec![], 1, 1000);
+
+        // Clocks deliberately disagree with wall-clock order: the higher-clock
+        // head has the earlier `end_time`, and vice versa. If `get_heads` fell
+        // back to sorting by `end_time` instead of the Lamport clock, the
+        // order asserted below would be reversed.
+        let high_clock_early_time = test_store.add_operation(vec![root.clone()], 10, 100);
+        let low_clock_late_time = test_store.add_operation(vec![root.clone()], 5, 9999);
+
+        let op_head_ids = vec![high_clock_early_time.clone(), low_clock_late_time.clone()];
+        let result = get_heads(
+            &op_store,
+            || op_head_ids.clone(),
+            |_deadline| None,
+            |op_heads| op_heads,
+            |op_heads| op_heads,
+        )
+        .unwrap();
+
+        match result {
+            OpHeads::Unresolved { op_heads, .. } => {
+                assert_eq!(
+                    op_heads.iter().map(|op| op.id().clone()).collect::<Vec<_>>(),
+                    vec![low_clock_late_time, high_clock_early_time]
+                );
+            }
+            OpHeads::Single(_) => panic!("expected divergent heads, got a single resolved head"),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright 2021-2022 The Jujutsu Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OperationId(Vec<u8>);
+
+impl OperationId {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        OperationId(bytes)
+    }
+
+    pub fn from_hex(hex_str: &str) -> Self {
+        OperationId(hex::decode(hex_str).unwrap())
+    }
+
+    pub fn hex(&self) -> String {
+        hex::encode(&self.0)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MillisSinceEpoch(pub i64);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    pub timestamp: MillisSinceEpoch,
+    pub tz_offset: i32,
+}
+
+/// Metadata recorded alongside every operation in the op log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OperationMetadata {
+    pub start_time: Timestamp,
+    pub end_time: Timestamp,
+    pub description: String,
+    pub hostname: String,
+    pub username: String,
+    pub tags: HashMap<String, String>,
+    /// Lamport logical clock used to order divergent op heads
+    /// deterministically, independent of (possibly skewed) wall-clock
+    /// time. Set to `1 + max(parent clocks)` when an operation is
+    /// created; defaults to 0 when reading operations written before
+    /// this field existed, which callers should treat as "no clock" and
+    /// fall back to `end_time` for ordering.
+    pub clock: u64,
+}
+
+/// Computes the Lamport clock value for a new operation given its
+/// parents' clocks. A root operation (no parents) gets clock 1, which
+/// keeps 0 free as the "no clock recorded" sentinel for legacy operations.
+///
+/// The op-heads store backends' `init` (e.g. `SimpleOpHeadsStore::init`)
+/// use this to set the clock of the root operation they create. Other
+/// operations take their `clock` from whatever builds their
+/// `OperationMetadata`, which is outside this tree; an operation whose
+/// `clock` is 0 falls into `OpOrderKey`'s `Legacy` (wall-clock) ordering
+/// bucket.
+pub fn next_clock(parent_clocks: &[u64]) -> u64 {
+    1 + parent_clocks.iter().copied().max().unwrap_or(0)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct View {
+    // Opaque to the op-heads store; it only ever reads/writes whole views.
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Operation {
+    pub view_id: ViewId,
+    pub parents: Vec<OperationId>,
+    pub metadata: OperationMetadata,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ViewId(Vec<u8>);
+
+#[derive(Debug)]
+pub struct OpStoreError(pub String);
+
+pub trait OpStore: Send + Sync + Debug {
+    fn write_view(&self, view: &View) -> Result<ViewId, OpStoreError>;
+
+    fn read_operation(&self, id: &OperationId) -> Result<Operation, OpStoreError>;
+
+    fn write_operation(&self, operation: &Operation) -> Result<OperationId, OpStoreError>;
+}
+
+#[cfg(test)]
+pub(crate) mod testutils {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Minimal in-memory `OpStore` for exercising op-heads store resolution
+    /// logic without a real backing store. Operation ids are assigned
+    /// sequentially rather than content-hashed, which is fine for tests
+    /// that only care about operation identity, not about matching real
+    /// jj's id scheme.
+    #[derive(Debug, Default)]
+    pub(crate) struct TestOpStore {
+        next_id: AtomicU64,
+        operations: Mutex<HashMap<OperationId, Operation>>,
+    }
+
+    impl TestOpStore {
+        pub(crate) fn new() -> Self {
+            TestOpStore::default()
+        }
+
+        /// `end_time_millis` only matters for operations with `clock == 0`,
+        /// which fall back to wall-clock ordering (see `OpOrderKey`).
+        pub(crate) fn test_metadata(clock: u64, end_time_millis: i64) -> OperationMetadata {
+            let timestamp = Timestamp {
+                timestamp: MillisSinceEpoch(end_time_millis),
+                tz_offset: 0,
+            };
+            OperationMetadata {
+                start_time: timestamp.clone(),
+                end_time: timestamp,
+                description: String::new(),
+                hostname: String::new(),
+                username: String::new(),
+                tags: HashMap::new(),
+                clock,
+            }
+        }
+
+        /// Writes an operation with `parents` and `clock`, returning its id.
+        pub(crate) fn add_operation(
+            &self,
+            parents: Vec<OperationId>,
+            clock: u64,
+            end_time_millis: i64,
+        ) -> OperationId {
+            let operation = Operation {
+                view_id: ViewId(vec![]),
+                parents,
+                metadata: Self::test_metadata(clock, end_time_millis),
+            };
+            self.write_operation(&operation).unwrap()
+        }
+    }
+
+    impl OpStore for TestOpStore {
+        fn write_view(&self, _view: &View) -> Result<ViewId, OpStoreError> {
+            Ok(ViewId(vec![]))
+        }
+
+        fn read_operation(&self, id: &OperationId) -> Result<Operation, OpStoreError> {
+            self.operations
+                .lock()
+                .unwrap()
+                .get(id)
+                .cloned()
+                .ok_or_else(|| OpStoreError(format!("no such operation: {}", id.hex())))
+        }
+
+        fn write_operation(&self, operation: &Operation) -> Result<OperationId, OpStoreError> {
+            let id = OperationId::new(
+                self.next_id
+                    .fetch_add(1, Ordering::SeqCst)
+                    .to_be_bytes()
+                    .to_vec(),
+            );
+            self.operations
+                .lock()
+                .unwrap()
+                .insert(id.clone(), operation.clone());
+            Ok(id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::next_clock;
+
+    #[test]
+    fn test_next_clock() {
+        assert_eq!(next_clock(&[]), 1);
+        assert_eq!(next_clock(&[1]), 2);
+        assert_eq!(next_clock(&[1, 5, 3]), 6);
+    }
+}
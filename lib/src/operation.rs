@@ -0,0 +1,59 @@
+// Copyright 2021-2022 The Jujutsu Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use crate::op_store::{self, OpStore, OperationId};
+
+/// An operation, with a reference back to the store it was read from so its
+/// parents can be read lazily.
+#[derive(Clone)]
+pub struct Operation {
+    op_store: Arc<dyn OpStore>,
+    id: OperationId,
+    operation: op_store::Operation,
+}
+
+impl Operation {
+    pub fn new(op_store: Arc<dyn OpStore>, id: OperationId, operation: op_store::Operation) -> Self {
+        Operation {
+            op_store,
+            id,
+            operation,
+        }
+    }
+
+    pub fn id(&self) -> &OperationId {
+        &self.id
+    }
+
+    pub fn store_operation(&self) -> &op_store::Operation {
+        &self.operation
+    }
+
+    pub fn parent_ids(&self) -> &[OperationId] {
+        &self.operation.parents
+    }
+
+    pub fn parents(&self) -> Vec<Operation> {
+        self.operation
+            .parents
+            .iter()
+            .map(|parent_id| {
+                let data = self.op_store.read_operation(parent_id).unwrap();
+                Operation::new(self.op_store.clone(), parent_id.clone(), data)
+            })
+            .collect()
+    }
+}
@@ -17,17 +17,58 @@ use std::fmt::{Debug, Formatter};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-
-use itertools::Itertools;
+use std::time::Duration;
 
 use crate::lock::FileLock;
 use crate::op_heads_store::{
-    LockedOpHeads, LockedOpHeadsResolver, OpHeadResolutionError, OpHeads, OpHeadsStore,
+    self, LockedOpHeads, LockedOpHeadsResolver, MigrationStep, ObsoletionTable,
+    OpHeadResolutionError, OpHeads, OpHeadsStore, Requirements,
 };
 use crate::op_store::{OpStore, OperationId, OperationMetadata};
 use crate::operation::Operation;
 use crate::{dag_walk, op_store};
 
+/// Identifies this backend in the `requirements` file.
+pub(crate) const BACKEND_NAME: &str = "simple_op_heads_store";
+
+/// On-disk format version of the loose-file layout (one empty file per
+/// head, inside a `simple_op_heads` subdirectory). Bump this and add a
+/// `MigrationStep` whenever the on-disk layout changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// TODO: Delete the 0->1 step once we're confident no op-heads stores at
+/// version 0 (pre-`requirements`-file, flat layout) remain in the wild.
+const MIGRATIONS: &[MigrationStep] = &[MigrationStep {
+    from_version: 0,
+    to_version: 1,
+    migrate: migrate_flat_files_to_subdir,
+}];
+
+/// Moves the legacy flat layout (one empty file per head directly in
+/// `dir`) into the `simple_op_heads` subdirectory used from version 1
+/// onward.
+///
+/// Idempotent, so it's safe to rerun on a directory that already has a
+/// `simple_op_heads` subdirectory (e.g. because the process died after this
+/// step but before `requirements.write` landed, so `load` reran this step):
+/// `create_dir_all` doesn't mind the subdirectory already existing, and any
+/// heads already moved over simply won't be found by `old_store` on the
+/// second pass.
+fn migrate_flat_files_to_subdir(dir: &Path) {
+    let op_heads_dir = dir.join("simple_op_heads");
+    let old_store = InnerSimpleOpHeadsStore {
+        dir: dir.to_path_buf(),
+    };
+    fs::create_dir_all(&op_heads_dir).unwrap();
+    let new_store = InnerSimpleOpHeadsStore {
+        dir: op_heads_dir,
+    };
+    for id in old_store.get_op_heads() {
+        old_store.remove_op_head(&id);
+        new_store.add_op_head(&id);
+    }
+}
+
 pub struct SimpleOpHeadsStore {
     store: Arc<InnerSimpleOpHeadsStore>,
 }
@@ -58,6 +99,9 @@ impl LockedOpHeadsResolver for SimpleOpHeadsStoreLockResolver {
         for old_id in new_op.parent_ids() {
             self.store.remove_op_head(old_id);
         }
+        let mut obsoletions = ObsoletionTable::read(&self.store.dir);
+        obsoletions.record(new_op.id().clone(), new_op.parent_ids().to_vec());
+        obsoletions.write(&self.store.dir);
     }
 }
 
@@ -111,8 +155,23 @@ impl InnerSimpleOpHeadsStore {
     /// Removes operations in the input that are ancestors of other operations
     /// in the input. The ancestors are removed both from the list and from
     /// disk.
+    ///
+    /// Tries the `ObsoletionTable` first, which answers the common case (one
+    /// head is a known-stale descendant of the other) with a table lookup
+    /// instead of a full `dag_walk`; falls back to `dag_walk` whenever the
+    /// table doesn't fully explain the current head set.
     /// TODO: Move this into the OpStore trait for sharing
     fn handle_ancestor_ops(&self, op_heads: Vec<Operation>) -> Vec<Operation> {
+        let table = ObsoletionTable::read(&self.dir);
+        if let Some(survivor) = table.prune(&op_heads) {
+            for op in &op_heads {
+                if op.id() != survivor.id() {
+                    self.remove_op_head(op.id());
+                }
+            }
+            return vec![survivor];
+        }
+
         let op_head_ids_before: HashSet<_> = op_heads.iter().map(|op| op.id().clone()).collect();
         let neighbors_fn = |op: &Operation| op.parents();
         // Remove ancestors so we don't create merge operation with an operation and its
@@ -124,6 +183,22 @@ impl InnerSimpleOpHeadsStore {
         }
         op_heads.into_iter().collect()
     }
+
+    /// Like `handle_ancestor_ops`, but never touches disk. Used when we
+    /// couldn't acquire the lock: we still want to hand back a sensible
+    /// merged view of the heads we *did* read, without racing a peer that
+    /// might be writing to the same files.
+    fn handle_ancestor_ops_readonly(&self, op_heads: Vec<Operation>) -> Vec<Operation> {
+        let table = ObsoletionTable::read(&self.dir);
+        if let Some(survivor) = table.prune(&op_heads) {
+            return vec![survivor];
+        }
+
+        let neighbors_fn = |op: &Operation| op.parents();
+        dag_walk::heads(op_heads, &neighbors_fn, &|op: &Operation| op.id().clone())
+            .into_iter()
+            .collect()
+    }
 }
 
 impl SimpleOpHeadsStore {
@@ -133,8 +208,17 @@ impl SimpleOpHeadsStore {
         root_view: &op_store::View,
         operation_metadata: OperationMetadata,
     ) -> (Self, Operation) {
+        let mut operation_metadata = operation_metadata;
+        operation_metadata.clock = op_store::next_clock(&[]);
         let (inner, init_op) =
             InnerSimpleOpHeadsStore::init(dir, op_store, root_view, operation_metadata);
+        // A freshly-created store is written at the current format version, so
+        // `load` won't try to migrate it later.
+        Requirements {
+            backend: BACKEND_NAME.to_string(),
+            version: FORMAT_VERSION,
+        }
+        .write(dir);
         (
             SimpleOpHeadsStore {
                 store: Arc::new(inner),
@@ -144,25 +228,10 @@ impl SimpleOpHeadsStore {
     }
 
     pub fn load(dir: &Path) -> Self {
-        let op_heads_dir = dir.join("simple_op_heads");
-
-        // TODO: Delete this migration code at 0.8+ or so
-        if !op_heads_dir.exists() {
-            let old_store = InnerSimpleOpHeadsStore {
-                dir: dir.to_path_buf(),
-            };
-            fs::create_dir(&op_heads_dir).unwrap();
-            let new_store = InnerSimpleOpHeadsStore { dir: op_heads_dir };
-
-            for id in old_store.get_op_heads() {
-                old_store.remove_op_head(&id);
-                new_store.add_op_head(&id);
-            }
-            return SimpleOpHeadsStore {
-                store: Arc::new(new_store),
-            };
-        }
+        let requirements = op_heads_store::migrate(dir, BACKEND_NAME, FORMAT_VERSION, MIGRATIONS);
+        requirements.write(dir);
 
+        let op_heads_dir = dir.join("simple_op_heads");
         SimpleOpHeadsStore {
             store: Arc::new(InnerSimpleOpHeadsStore { dir: op_heads_dir }),
         }
@@ -171,7 +240,7 @@ impl SimpleOpHeadsStore {
 
 impl OpHeadsStore for SimpleOpHeadsStore {
     fn name(&self) -> &str {
-        "simple_op_heads_store"
+        BACKEND_NAME
     }
 
     fn add_op_head(&self, id: &OperationId) {
@@ -194,68 +263,26 @@ impl OpHeadsStore for SimpleOpHeadsStore {
         }))
     }
 
-    fn get_heads(&self, op_store: &Arc<dyn OpStore>) -> Result<OpHeads, OpHeadResolutionError> {
-        let mut op_heads = self.get_op_heads();
-
-        if op_heads.is_empty() {
-            return Err(OpHeadResolutionError::NoHeads);
-        }
-
-        if op_heads.len() == 1 {
-            let operation_id = op_heads.pop().unwrap();
-            let operation = op_store.read_operation(&operation_id).unwrap();
-            return Ok(OpHeads::Single(Operation::new(
-                op_store.clone(),
-                operation_id,
-                operation,
-            )));
-        }
-
-        // There are multiple heads. We take a lock, then check if there are still
-        // multiple heads (it's likely that another process was in the process of
-        // deleting on of them). If there are still multiple heads, we attempt to
-        // merge all the views into one. We then write that view and a corresponding
-        // operation to the op-store.
-        // Note that the locking isn't necessary for correctness; we take the lock
-        // only to prevent other concurrent processes from doing the same work (and
-        // producing another set of divergent heads).
-        let locked_op_heads = self.lock();
-        let op_head_ids = self.get_op_heads();
-
-        if op_head_ids.is_empty() {
-            return Err(OpHeadResolutionError::NoHeads);
-        }
-
-        if op_head_ids.len() == 1 {
-            let op_head_id = op_head_ids[0].clone();
-            let op_head = op_store.read_operation(&op_head_id).unwrap();
-            // Return early so we don't write a merge operation with a single parent
-            return Ok(OpHeads::Single(Operation::new(
-                op_store.clone(),
-                op_head_id,
-                op_head,
-            )));
-        }
-
-        let op_heads = op_head_ids
-            .iter()
-            .map(|op_id: &OperationId| {
-                let data = op_store.read_operation(op_id).unwrap();
-                Operation::new(op_store.clone(), op_id.clone(), data)
-            })
-            .collect_vec();
-        let mut op_heads = self.store.handle_ancestor_ops(op_heads);
-
-        // Return without creating a merge operation
-        if op_heads.len() == 1 {
-            return Ok(OpHeads::Single(op_heads.pop().unwrap()));
-        }
+    /// Like `lock`, but never blocks indefinitely: retries with backoff
+    /// until `deadline` elapses, then gives up and returns `None` instead
+    /// of hanging behind a lock held by a stuck or dead process (or one
+    /// that simply doesn't work on the underlying filesystem).
+    fn try_lock(&self, deadline: Duration) -> Option<LockedOpHeads> {
+        let lock = op_heads_store::try_lock_with_backoff(&self.store.dir.join("lock"), deadline)?;
+        Some(LockedOpHeads::new(Box::new(SimpleOpHeadsStoreLockResolver {
+            store: self.store.clone(),
+            _lock: lock,
+        })))
+    }
 
-        op_heads.sort_by_key(|op| op.store_operation().metadata.end_time.timestamp.clone());
-        Ok(OpHeads::Unresolved {
-            locked_op_heads,
-            op_heads,
-        })
+    fn get_heads(&self, op_store: &Arc<dyn OpStore>) -> Result<OpHeads, OpHeadResolutionError> {
+        op_heads_store::get_heads(
+            op_store,
+            || self.get_op_heads(),
+            |deadline| self.try_lock(deadline),
+            |op_heads| self.store.handle_ancestor_ops(op_heads),
+            |op_heads| self.store.handle_ancestor_ops_readonly(op_heads),
+        )
     }
 }
 
@@ -264,12 +291,16 @@ mod tests {
     use std::collections::HashSet;
     use std::fs;
     use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
 
     use itertools::Itertools;
 
     use super::InnerSimpleOpHeadsStore;
-    use crate::op_heads_store::OpHeadsStore;
-    use crate::op_store::OperationId;
+    use crate::lock::FileLock;
+    use crate::op_heads_store::{OpHeads, OpHeadsStore};
+    use crate::op_store::testutils::TestOpStore;
+    use crate::op_store::{self, OpStore, OperationId};
     use crate::simple_op_heads_store::SimpleOpHeadsStore;
 
     fn read_dir(dir: &Path) -> Vec<String> {
@@ -303,7 +334,10 @@ mod tests {
 
         let new_store = SimpleOpHeadsStore::load(&store_path);
         assert_eq!(&ops, &new_store.get_op_heads().into_iter().collect());
-        assert_eq!(vec!["simple_op_heads"], read_dir(&store_path));
+        assert_eq!(
+            vec!["requirements", "simple_op_heads"],
+            read_dir(&store_path)
+        );
         assert_eq!(
             vec!["012345", "abcdef"],
             read_dir(&store_path.join("simple_op_heads"))
@@ -312,10 +346,85 @@ mod tests {
         // Migration is idempotent
         let new_store = SimpleOpHeadsStore::load(&store_path);
         assert_eq!(&ops, &new_store.get_op_heads().into_iter().collect());
-        assert_eq!(vec!["simple_op_heads"], read_dir(&store_path));
+        assert_eq!(
+            vec!["requirements", "simple_op_heads"],
+            read_dir(&store_path)
+        );
         assert_eq!(
             vec!["012345", "abcdef"],
             read_dir(&store_path.join("simple_op_heads"))
         );
     }
+
+    #[test]
+    fn test_simple_op_heads_store_current_version_skips_migration() {
+        let test_dir = testutils::new_temp_dir();
+        let store_path = test_dir.path().join("op_heads");
+        fs::create_dir(&store_path).unwrap();
+
+        let requirements = crate::op_heads_store::Requirements {
+            backend: super::BACKEND_NAME.to_string(),
+            version: super::FORMAT_VERSION,
+        };
+        requirements.write(&store_path);
+        fs::create_dir(store_path.join("simple_op_heads")).unwrap();
+
+        // Loading an already-current store must not run any migration, so the
+        // legacy flat-layout step is never invoked on a fresh store.
+        let loaded = crate::op_heads_store::Requirements::read(&store_path, super::BACKEND_NAME);
+        assert_eq!(loaded.version, super::FORMAT_VERSION);
+        let migrated = crate::op_heads_store::migrate(
+            &store_path,
+            super::BACKEND_NAME,
+            super::FORMAT_VERSION,
+            super::MIGRATIONS,
+        );
+        assert_eq!(migrated.version, super::FORMAT_VERSION);
+        assert_eq!(
+            vec!["requirements", "simple_op_heads"],
+            read_dir(&store_path)
+        );
+    }
+
+    #[test]
+    fn test_simple_op_heads_store_get_heads_falls_back_when_locked() {
+        let test_dir = testutils::new_temp_dir();
+        let store_path = test_dir.path().join("op_heads");
+        fs::create_dir(&store_path).unwrap();
+
+        let test_op_store = Arc::new(TestOpStore::new());
+        let op_store: Arc<dyn OpStore> = test_op_store.clone();
+        let root_view = op_store::View {};
+        let (store, root_op) = SimpleOpHeadsStore::init(
+            &store_path,
+            &op_store,
+            &root_view,
+            TestOpStore::test_metadata(1, 0),
+        );
+
+        // Replace the single root head with two divergent children, neither of
+        // which is an ancestor of the other.
+        let child1 = test_op_store.add_operation(vec![root_op.id().clone()], 2, 0);
+        let child2 = test_op_store.add_operation(vec![root_op.id().clone()], 2, 0);
+        store.remove_op_head(root_op.id());
+        store.add_op_head(&child1);
+        store.add_op_head(&child2);
+
+        // Hold the store's lock like a concurrent process would, so the
+        // `try_lock` inside `get_heads` can't acquire it and must fall back to
+        // read-only reconciliation instead of blocking indefinitely.
+        let _external_lock = FileLock::lock(store_path.join("simple_op_heads").join("lock"));
+
+        let started = Instant::now();
+        let result = store.get_heads(&op_store).unwrap();
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "get_heads should fall back, not block, when it can't acquire the lock"
+        );
+
+        match result {
+            OpHeads::Unresolved { op_heads, .. } => assert_eq!(op_heads.len(), 2),
+            OpHeads::Single(_) => panic!("expected divergent heads, got a single resolved head"),
+        }
+    }
 }
\ No newline at end of file
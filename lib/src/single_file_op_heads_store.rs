@@ -0,0 +1,417 @@
+// Copyright 2021-2022 The Jujutsu Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashSet;
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use itertools::Itertools;
+
+use crate::lock::FileLock;
+use crate::op_heads_store::{
+    self, LockedOpHeads, LockedOpHeadsResolver, MigrationStep, ObsoletionTable,
+    OpHeadResolutionError, OpHeads, OpHeadsStore, Requirements,
+};
+use crate::op_store::{OpStore, OperationId, OperationMetadata};
+use crate::operation::Operation;
+use crate::{dag_walk, op_store};
+
+/// Identifies this backend in the `requirements` file.
+pub(crate) const BACKEND_NAME: &str = "single_file_op_heads_store";
+
+/// On-disk format version of the docket layout.
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+/// This backend has no predecessor of its own, so there is nothing to
+/// migrate from; a directory only reaches version 1 by being created by
+/// `init`, or by `op_heads_store::migrate_to_single_file` switching a
+/// `simple_op_heads_store` directory over.
+const MIGRATIONS: &[MigrationStep] = &[];
+
+/// A single file ("docket") recording the current set of op heads, instead
+/// of one empty file per head. Good for large or NFS-hosted repos, where a
+/// `simple_op_heads` directory with thousands of entries means a full
+/// `read_dir` on every `get_op_heads` and a separate `create`/`unlink`
+/// syscall per add/remove.
+///
+/// The docket's first line is the format version and the rest are the
+/// current head ids, one hex id per line. Adds and removes are done by
+/// rewriting the whole file under `FileLock`, then atomically renaming it
+/// into place so readers never observe a half-written docket.
+struct InnerSingleFileOpHeadsStore {
+    dir: PathBuf,
+}
+
+impl InnerSingleFileOpHeadsStore {
+    fn docket_path(&self) -> PathBuf {
+        self.dir.join("docket")
+    }
+
+    fn read_heads(&self) -> Vec<OperationId> {
+        match fs::read_to_string(self.docket_path()) {
+            Ok(content) => content
+                .lines()
+                .skip(1)
+                .filter_map(|line| hex::decode(line).ok())
+                .map(OperationId::new)
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    fn write_heads(&self, heads: &[OperationId]) {
+        let mut content = format!("{FORMAT_VERSION}\n");
+        for head in heads {
+            content.push_str(&head.hex());
+            content.push('\n');
+        }
+        // Write to a temporary file and rename it into place so a reader never
+        // sees a partially-written docket.
+        let tmp_path = self.dir.join("docket.tmp");
+        fs::write(&tmp_path, content).unwrap();
+        fs::rename(&tmp_path, self.docket_path()).unwrap();
+    }
+
+    pub fn add_op_head(&self, id: &OperationId) {
+        let _lock = FileLock::lock(self.dir.join("lock"));
+        self.add_op_head_locked(id);
+    }
+
+    /// Like `add_op_head`, but assumes the caller already holds
+    /// `self.dir.join("lock")` (e.g. because it's inside a
+    /// `LockedOpHeadsResolver::finish` or a `handle_ancestor_ops_locked`
+    /// call). Taking the lock again here would deadlock, since `FileLock` is
+    /// not reentrant.
+    fn add_op_head_locked(&self, id: &OperationId) {
+        let mut heads = self.read_heads();
+        if !heads.contains(id) {
+            heads.push(id.clone());
+            self.write_heads(&heads);
+        }
+    }
+
+    pub fn remove_op_head(&self, id: &OperationId) {
+        let _lock = FileLock::lock(self.dir.join("lock"));
+        self.remove_op_head_locked(id);
+    }
+
+    /// See `add_op_head_locked`.
+    fn remove_op_head_locked(&self, id: &OperationId) {
+        let mut heads = self.read_heads();
+        heads.retain(|head| head != id);
+        self.write_heads(&heads);
+    }
+
+    pub fn get_op_heads(&self) -> Vec<OperationId> {
+        self.read_heads()
+    }
+
+    /// See `SimpleOpHeadsStore::handle_ancestor_ops`: tries the
+    /// `ObsoletionTable` before falling back to a full `dag_walk`. Assumes
+    /// the caller already holds `self.dir.join("lock")` (this is only ever
+    /// called from `get_heads`'s locked branch, via `try_lock`); taking the
+    /// lock again here would deadlock against ourselves.
+    fn handle_ancestor_ops_locked(&self, op_heads: Vec<Operation>) -> Vec<Operation> {
+        let table = ObsoletionTable::read(&self.dir);
+        if let Some(survivor) = table.prune(&op_heads) {
+            let mut heads = self.read_heads();
+            heads.retain(|head| head == survivor.id());
+            self.write_heads(&heads);
+            return vec![survivor];
+        }
+
+        let op_head_ids_before: HashSet<_> = op_heads.iter().map(|op| op.id().clone()).collect();
+        let neighbors_fn = |op: &Operation| op.parents();
+        let op_heads = dag_walk::heads(op_heads, &neighbors_fn, &|op: &Operation| op.id().clone());
+        let op_head_ids_after: HashSet<_> = op_heads.iter().map(|op| op.id().clone()).collect();
+        if op_head_ids_after.len() != op_head_ids_before.len() {
+            let mut heads = self.read_heads();
+            heads.retain(|head| op_head_ids_after.contains(head));
+            self.write_heads(&heads);
+        }
+        op_heads.into_iter().collect()
+    }
+
+    /// Like `handle_ancestor_ops_locked`, but never touches disk.
+    fn handle_ancestor_ops_readonly(&self, op_heads: Vec<Operation>) -> Vec<Operation> {
+        let table = ObsoletionTable::read(&self.dir);
+        if let Some(survivor) = table.prune(&op_heads) {
+            return vec![survivor];
+        }
+
+        let neighbors_fn = |op: &Operation| op.parents();
+        dag_walk::heads(op_heads, &neighbors_fn, &|op: &Operation| op.id().clone())
+            .into_iter()
+            .collect()
+    }
+}
+
+struct SingleFileOpHeadsStoreLockResolver {
+    store: Arc<InnerSingleFileOpHeadsStore>,
+    _lock: FileLock,
+}
+
+impl LockedOpHeadsResolver for SingleFileOpHeadsStoreLockResolver {
+    fn finish(&self, new_op: &Operation) {
+        // `self._lock` is already held, so use the lock-already-held variants
+        // here rather than `add_op_head`/`remove_op_head`, which would try to
+        // (re-)acquire the same lock and deadlock.
+        self.store.add_op_head_locked(new_op.id());
+        for old_id in new_op.parent_ids() {
+            self.store.remove_op_head_locked(old_id);
+        }
+        let mut obsoletions = ObsoletionTable::read(&self.store.dir);
+        obsoletions.record(new_op.id().clone(), new_op.parent_ids().to_vec());
+        obsoletions.write(&self.store.dir);
+    }
+}
+
+pub struct SingleFileOpHeadsStore {
+    store: Arc<InnerSingleFileOpHeadsStore>,
+}
+
+impl Debug for SingleFileOpHeadsStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SingleFileOpHeadsStore")
+            .field("dir", &self.store.dir)
+            .finish()
+    }
+}
+
+impl SingleFileOpHeadsStore {
+    pub fn init(
+        dir: &Path,
+        op_store: &Arc<dyn OpStore>,
+        root_view: &op_store::View,
+        operation_metadata: OperationMetadata,
+    ) -> (Self, Operation) {
+        let mut operation_metadata = operation_metadata;
+        operation_metadata.clock = op_store::next_clock(&[]);
+        let root_view_id = op_store.write_view(root_view).unwrap();
+        let init_operation = op_store::Operation {
+            view_id: root_view_id,
+            parents: vec![],
+            metadata: operation_metadata,
+        };
+        let init_operation_id = op_store.write_operation(&init_operation).unwrap();
+        let init_operation = Operation::new(op_store.clone(), init_operation_id, init_operation);
+
+        let op_heads_dir = dir.join("single_file_op_heads");
+        fs::create_dir(&op_heads_dir).unwrap();
+        let inner = InnerSingleFileOpHeadsStore { dir: op_heads_dir };
+        inner.add_op_head(init_operation.id());
+
+        // A freshly-created store is written at the current format version, so
+        // `load` won't try to migrate it later.
+        Requirements {
+            backend: BACKEND_NAME.to_string(),
+            version: FORMAT_VERSION,
+        }
+        .write(dir);
+
+        (
+            SingleFileOpHeadsStore {
+                store: Arc::new(inner),
+            },
+            init_operation,
+        )
+    }
+
+    pub fn load(dir: &Path) -> Self {
+        let requirements = op_heads_store::migrate(dir, BACKEND_NAME, FORMAT_VERSION, MIGRATIONS);
+        requirements.write(dir);
+
+        let op_heads_dir = dir.join("single_file_op_heads");
+        SingleFileOpHeadsStore {
+            store: Arc::new(InnerSingleFileOpHeadsStore { dir: op_heads_dir }),
+        }
+    }
+
+    /// Reads the loose-file layout written by `SimpleOpHeadsStore` and
+    /// writes the consolidated docket in its place. Used as a
+    /// `simple_op_heads_store` -> `single_file_op_heads_store` migration
+    /// step once a repo opts into this backend.
+    ///
+    /// Idempotent (safe to rerun if a crash left `requirements` unwritten
+    /// after this step completed, same as `migrate_flat_files_to_subdir`):
+    /// `create_dir_all` tolerates the subdirectory already existing, and
+    /// `write_heads` is itself a tmp+rename, so a retry just rewrites the
+    /// docket with the same legacy heads.
+    pub fn migrate_from_simple_op_heads(dir: &Path) {
+        let legacy_dir = dir.join("simple_op_heads");
+        let legacy_heads = match fs::read_dir(&legacy_dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+                .filter_map(|name| hex::decode(name).ok())
+                .map(OperationId::new)
+                .collect_vec(),
+            Err(_) => vec![],
+        };
+
+        let op_heads_dir = dir.join("single_file_op_heads");
+        fs::create_dir_all(&op_heads_dir).unwrap();
+        let inner = InnerSingleFileOpHeadsStore { dir: op_heads_dir };
+        inner.write_heads(&legacy_heads);
+    }
+}
+
+impl OpHeadsStore for SingleFileOpHeadsStore {
+    fn name(&self) -> &str {
+        BACKEND_NAME
+    }
+
+    fn add_op_head(&self, id: &OperationId) {
+        self.store.add_op_head(id);
+    }
+
+    fn remove_op_head(&self, id: &OperationId) {
+        self.store.remove_op_head(id);
+    }
+
+    fn get_op_heads(&self) -> Vec<OperationId> {
+        self.store.get_op_heads()
+    }
+
+    fn lock(&self) -> LockedOpHeads {
+        let lock = FileLock::lock(self.store.dir.join("lock"));
+        LockedOpHeads::new(Box::new(SingleFileOpHeadsStoreLockResolver {
+            store: self.store.clone(),
+            _lock: lock,
+        }))
+    }
+
+    fn try_lock(&self, deadline: Duration) -> Option<LockedOpHeads> {
+        let lock = op_heads_store::try_lock_with_backoff(&self.store.dir.join("lock"), deadline)?;
+        Some(LockedOpHeads::new(Box::new(
+            SingleFileOpHeadsStoreLockResolver {
+                store: self.store.clone(),
+                _lock: lock,
+            },
+        )))
+    }
+
+    fn get_heads(&self, op_store: &Arc<dyn OpStore>) -> Result<OpHeads, OpHeadResolutionError> {
+        // The locked branch runs while `try_lock` is still holding the lock
+        // (see `get_heads`'s doc comment), so it must use
+        // `handle_ancestor_ops_locked` rather than a self-locking variant.
+        op_heads_store::get_heads(
+            op_store,
+            || self.get_op_heads(),
+            |deadline| self.try_lock(deadline),
+            |op_heads| self.store.handle_ancestor_ops_locked(op_heads),
+            |op_heads| self.store.handle_ancestor_ops_readonly(op_heads),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::sync::Arc;
+
+    use super::{InnerSingleFileOpHeadsStore, SingleFileOpHeadsStore};
+    use crate::op_heads_store::{ObsoletionTable, OpHeads, OpHeadsStore};
+    use crate::op_store::testutils::TestOpStore;
+    use crate::op_store::{self, OpStore, OperationId};
+
+    #[test]
+    fn test_single_file_op_heads_store_add_remove() {
+        let test_dir = testutils::new_temp_dir();
+        let store_path = test_dir.path().join("op_heads");
+        fs::create_dir(&store_path).unwrap();
+
+        let op_heads_dir = store_path.join("single_file_op_heads");
+        fs::create_dir(&op_heads_dir).unwrap();
+        let inner = InnerSingleFileOpHeadsStore { dir: op_heads_dir };
+
+        let op1 = OperationId::from_hex("012345");
+        let op2 = OperationId::from_hex("abcdef");
+        inner.add_op_head(&op1);
+        inner.add_op_head(&op2);
+
+        let heads: HashSet<_> = inner.get_op_heads().into_iter().collect();
+        assert_eq!(heads, HashSet::from([op1.clone(), op2.clone()]));
+
+        inner.remove_op_head(&op1);
+        assert_eq!(inner.get_op_heads(), vec![op2]);
+    }
+
+    #[test]
+    fn test_single_file_op_heads_store_migration_from_simple() {
+        let test_dir = testutils::new_temp_dir();
+        let store_path = test_dir.path().join("op_heads");
+        fs::create_dir(&store_path).unwrap();
+        let legacy_dir = store_path.join("simple_op_heads");
+        fs::create_dir(&legacy_dir).unwrap();
+        fs::write(legacy_dir.join("012345"), "").unwrap();
+        fs::write(legacy_dir.join("abcdef"), "").unwrap();
+
+        SingleFileOpHeadsStore::migrate_from_simple_op_heads(&store_path);
+
+        let op_heads_dir = store_path.join("single_file_op_heads");
+        let inner = InnerSingleFileOpHeadsStore { dir: op_heads_dir };
+        let heads: HashSet<_> = inner.get_op_heads().into_iter().collect();
+        assert_eq!(
+            heads,
+            HashSet::from([
+                OperationId::from_hex("012345"),
+                OperationId::from_hex("abcdef"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_single_file_op_heads_store_get_heads_prunes_ancestor_head() {
+        let test_dir = testutils::new_temp_dir();
+        let store_path = test_dir.path().join("op_heads");
+        fs::create_dir(&store_path).unwrap();
+
+        let test_op_store = Arc::new(TestOpStore::new());
+        let op_store: Arc<dyn OpStore> = test_op_store.clone();
+        let root_view = op_store::View {};
+        let (store, root_op) = SingleFileOpHeadsStore::init(
+            &store_path,
+            &op_store,
+            &root_view,
+            TestOpStore::test_metadata(1, 0),
+        );
+
+        // `child` is a descendant of `root_op`, and the obsoletion table
+        // records that relationship, as `LockedOpHeadsResolver::finish` would.
+        // Both are left as heads on disk, as if a racing process added `child`
+        // but hasn't removed `root_op` yet.
+        let child_id = test_op_store.add_operation(vec![root_op.id().clone()], 2, 0);
+        store.add_op_head(&child_id);
+        let op_heads_dir = store_path.join("single_file_op_heads");
+        let mut obsoletions = ObsoletionTable::read(&op_heads_dir);
+        obsoletions.record(child_id.clone(), vec![root_op.id().clone()]);
+        obsoletions.write(&op_heads_dir);
+
+        // This is the exact scenario that used to deadlock (chunk0-3):
+        // resolving two heads where one is an ancestor of the other, through
+        // `get_heads()`, while the `ObsoletionTable` fast path (chunk0-5) is
+        // wired into the locked branch.
+        let result = store.get_heads(&op_store).unwrap();
+        match result {
+            OpHeads::Single(op) => assert_eq!(op.id(), &child_id),
+            OpHeads::Unresolved { .. } => panic!("expected the ancestor head to be pruned"),
+        }
+        assert_eq!(store.get_op_heads(), vec![child_id]);
+    }
+}